@@ -15,32 +15,121 @@
 //! Windows main loop.
 
 use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
 use std::mem;
+use std::os::windows::io::AsRawHandle;
+use std::os::windows::process::ExitStatusExt;
+use std::process::{Child, ExitStatus};
 use std::ptr::null_mut;
 use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use winapi::shared::minwindef::{DWORD, FALSE, TRUE};
 use winapi::shared::winerror::*;
+use winapi::um::fileapi::{ReadFile, WriteFile};
+use winapi::um::namedpipeapi::PeekNamedPipe;
+use winapi::um::processthreadsapi::GetExitCodeProcess;
+use winapi::um::synchapi::{CreateEventW, ResetEvent, SetEvent, WaitForMultipleObjects};
 use winapi::um::winbase::*;
 use winapi::um::winnt::*;
 use winapi::um::winuser::*;
 
+/// `MsgWaitForMultipleObjectsEx` caps out at `MAXIMUM_WAIT_OBJECTS` (64)
+/// handles, one slot of which is implicitly reserved for the message
+/// queue, so at most this many real handles can be waited on directly.
+const MAX_WAIT_OBJECTS_MAIN: usize = MAXIMUM_WAIT_OBJECTS as usize - 1;
+
 #[derive(Clone, Default)]
 pub struct RunLoopHandle(Rc<RefCell<RunLoopState>>);
 
 #[derive(Default)]
 struct RunLoopState {
     listeners: Vec<Listener>,
+    next_listener_id: ListenerId,
     idle: Vec<Box<IdleCallback>>,
+    timers: BinaryHeap<Reverse<(Instant, TimerId)>>,
+    timer_entries: HashMap<TimerId, TimerEntry>,
+    next_timer_id: TimerId,
+    pausable_idle: Vec<PausableIdle>,
+    next_idle_id: IdleId,
+}
+
+type IdleId = u64;
+
+/// Opaque handle to a pausable idle handler registered with
+/// `add_pausable_idle`, used to pause, resume, or remove it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct IdleToken(IdleId);
+
+struct PausableIdle {
+    id: IdleId,
+    paused: bool,
+    callback: Box<FnMut()>,
 }
 
 struct Listener {
+    id: ListenerId,
     h: HANDLE,
     callback: Box<FnMut()>,
 }
 
+type ListenerId = u64;
+
+/// Opaque handle to a listener registered with `add_handler`, used to
+/// remove it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ListenerToken(ListenerId);
+
+/// A group of overflow listener handles (beyond the first
+/// `MAX_WAIT_OBJECTS_MAIN - 1`, which share the main wait with the
+/// aggregating event) being watched by a dedicated background
+/// thread, which signals `aggregate_event` when one of them fires.
+struct OverflowGroup {
+    stop_event: HANDLE,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+type TimerId = u64;
+
+/// Opaque handle to a timer registered with `add_timer` or `add_timeout`,
+/// used to cancel it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct TimerToken(TimerId);
+
+struct TimerEntry {
+    // `Some` for a repeating timer registered with `add_timer`, `None` for
+    // a one-shot timeout registered with `add_timeout`.
+    repeat: Option<Duration>,
+    callback: Box<FnMut()>,
+}
+
 pub struct RunLoop {
     handle: RunLoopHandle,
+    proxy_queue: Arc<Mutex<VecDeque<Box<IdleCallback + Send>>>>,
+    proxy_event: HANDLE,
+    // Overflow listener groups (beyond `MAX_WAIT_OBJECTS_MAIN - 1`), each
+    // serviced by its own background wait thread. `overflow_event` is the
+    // single handle added to the main wait set to represent all of them.
+    overflow_groups: Vec<OverflowGroup>,
+    overflow_signature: Vec<Vec<ListenerId>>,
+    overflow_event: Option<HANDLE>,
+    overflow_ready: Arc<Mutex<VecDeque<ListenerId>>>,
 }
 
+/// A handle that can be used from any thread to schedule a closure to run
+/// on the run loop's thread, by way of a manual-reset event the run loop
+/// listens on.
+#[derive(Clone)]
+pub struct RunLoopProxy {
+    queue: Arc<Mutex<VecDeque<Box<IdleCallback + Send>>>>,
+    event: HANDLE,
+}
+
+unsafe impl Send for RunLoopProxy {}
+unsafe impl Sync for RunLoopProxy {}
+
 pub trait IdleCallback {
     fn call(self: Box<Self>);
 }
@@ -53,8 +142,33 @@ impl<F: FnOnce()> IdleCallback for F {
 
 impl RunLoop {
     pub fn new() -> RunLoop {
+        let proxy_queue = Arc::new(Mutex::new(VecDeque::new()));
+        let proxy_event = unsafe { CreateEventW(null_mut(), TRUE, FALSE, null_mut()) };
+        let handle = RunLoopHandle::default();
+        let queue = proxy_queue.clone();
+        unsafe {
+            let _ = handle.add_handler(proxy_event, move || {
+                // Reset *before* draining: if a producer's `push_back` +
+                // `SetEvent` lands between our drain and the reset, this
+                // ordering means the reset can only ever clobber a
+                // `SetEvent` for work we're about to drain anyway (or one
+                // that lands after, which leaves the event signaled for
+                // next time) — never one for work left behind unsignaled.
+                ResetEvent(proxy_event);
+                let pending = mem::replace(&mut *queue.lock().unwrap(), VecDeque::new());
+                for callback in pending {
+                    callback.call();
+                }
+            });
+        }
         RunLoop {
-            handle: Default::default(),
+            handle,
+            proxy_queue,
+            proxy_event,
+            overflow_groups: Vec::new(),
+            overflow_signature: Vec::new(),
+            overflow_event: None,
+            overflow_ready: Arc::new(Mutex::new(VecDeque::new())),
         }
     }
 
@@ -64,20 +178,57 @@ impl RunLoop {
         self.handle.clone()
     }
 
+    /// Create a `Send + Sync` proxy that lets other threads post closures
+    /// to be run on this run loop's thread.
+    pub fn create_proxy(&self) -> RunLoopProxy {
+        RunLoopProxy {
+            queue: self.proxy_queue.clone(),
+            event: self.proxy_event,
+        }
+    }
+
     pub fn run(&mut self) {
 
         unsafe {
             loop {
-                let mut handles = Vec::new();
-                for listener in &self.handle.0.borrow().listeners {
-                    handles.push(listener.h);
+                let all: Vec<(ListenerId, HANDLE)> = self.handle.0.borrow().listeners
+                    .iter()
+                    .map(|listener| (listener.id, listener.h))
+                    .collect();
+                let (main_group, overflow_chunks) = if all.len() <= MAX_WAIT_OBJECTS_MAIN {
+                    (all, Vec::new())
+                } else {
+                    // One of the `MAX_WAIT_OBJECTS_MAIN` slots has to hold
+                    // the aggregating event for the overflow groups, so
+                    // only `MAX_WAIT_OBJECTS_MAIN - 1` listeners can go in
+                    // `main_group` once overflow is in play.
+                    let (main, rest) = all.split_at(MAX_WAIT_OBJECTS_MAIN - 1);
+                    let chunks = rest.chunks(MAX_WAIT_OBJECTS_MAIN).map(|c| c.to_vec()).collect();
+                    (main.to_vec(), chunks)
+                };
+                self.reconcile_overflow_groups(&overflow_chunks);
+
+                let mut handles: Vec<HANDLE> = main_group.iter().map(|&(_, h)| h).collect();
+                if !overflow_chunks.is_empty() {
+                    handles.push(self.overflow_event.expect("overflow_event set by reconcile_overflow_groups"));
                 }
                 let len = handles.len() as u32;
-                let has_idle = !self.handle.0.borrow().idle.is_empty();
+                let has_idle = !self.handle.0.borrow().idle.is_empty()
+                    || self.handle.0.borrow().pausable_idle.iter().any(|idle| !idle.paused);
+                let next_deadline = self.handle.0.borrow().timers.peek()
+                    .map(|&Reverse((deadline, _))| deadline);
+                let timeout = if has_idle {
+                    0
+                } else {
+                    match next_deadline {
+                        Some(deadline) => duration_to_millis(deadline.saturating_duration_since(Instant::now())),
+                        None => INFINITE,
+                    }
+                };
                 let res = MsgWaitForMultipleObjectsEx(
                     len,
                     handles.as_ptr(),
-                    if has_idle { 0 } else { INFINITE },
+                    timeout,
                     QS_ALLEVENTS,
                     0
                 );
@@ -98,20 +249,214 @@ impl RunLoop {
                     DispatchMessageW(&mut msg);
                 }
 
+                self.fire_timers();
+
                 if res >= WAIT_OBJECT_0 && res < WAIT_OBJECT_0 + len {
                     let ix = (res - WAIT_OBJECT_0) as usize;
-                    (&mut self.handle.0.borrow_mut().listeners[ix].callback)();
+                    if ix < main_group.len() {
+                        self.dispatch_listener(main_group[ix].0);
+                    } else {
+                        // The aggregating event for one or more overflow
+                        // groups fired; drain the ready queue they've been
+                        // filling and dispatch each on the main thread.
+                        let ready: Vec<ListenerId> = self.overflow_ready.lock().unwrap().drain(..).collect();
+                        ResetEvent(self.overflow_event.unwrap());
+                        for id in ready {
+                            self.dispatch_listener(id);
+                        }
+                        // The wait threads have dropped the handles they
+                        // reported; force the next reconcile to respawn the
+                        // groups so still-registered listeners are watched
+                        // again.
+                        self.overflow_signature.clear();
+                    }
                 } else if res == WAIT_TIMEOUT {
                     let idles = mem::replace(&mut self.handle.0.borrow_mut().idle, Vec::new());
                     for callback in idles {
                         callback.call();
                     }
+                    let mut i = 0;
+                    loop {
+                        let should_call = match self.handle.0.borrow().pausable_idle.get(i) {
+                            Some(idle) => !idle.paused,
+                            None => break,
+                        };
+                        if should_call {
+                            // Swap the callback out while it runs, so it can
+                            // pause, resume or remove itself without a
+                            // double borrow of `RunLoopState`.
+                            let (id, mut callback) = {
+                                let mut state = self.handle.0.borrow_mut();
+                                let idle = &mut state.pausable_idle[i];
+                                (idle.id, mem::replace(&mut idle.callback, Box::new(|| {})))
+                            };
+                            callback();
+                            let mut state = self.handle.0.borrow_mut();
+                            if let Some(idle) = state.pausable_idle.iter_mut().find(|idle| idle.id == id) {
+                                idle.callback = callback;
+                            }
+                        }
+                        i += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Call the listener with the given id, if it's still registered.
+    /// Fetches the callback through a raw pointer rather than holding the
+    /// `RefMut` across the call, so a callback that unregisters itself
+    /// (e.g. a process watcher) doesn't double-borrow `RunLoopState`.
+    fn dispatch_listener(&self, id: ListenerId) {
+        let callback = {
+            let mut state = self.handle.0.borrow_mut();
+            state.listeners.iter_mut()
+                .find(|listener| listener.id == id)
+                .map(|listener| &mut *listener.callback as *mut FnMut())
+        };
+        if let Some(callback) = callback {
+            unsafe {
+                (*callback)();
+            }
+        }
+    }
+
+    /// Spawn or tear down overflow wait threads so they match `chunks`,
+    /// the current partition of listener handles beyond the first
+    /// `MAX_WAIT_OBJECTS_MAIN - 1`.
+    fn reconcile_overflow_groups(&mut self, chunks: &[Vec<(ListenerId, HANDLE)>]) {
+        let signature: Vec<Vec<ListenerId>> = chunks.iter()
+            .map(|chunk| chunk.iter().map(|&(id, _)| id).collect())
+            .collect();
+        if signature == self.overflow_signature {
+            return;
+        }
+        for group in self.overflow_groups.drain(..) {
+            unsafe {
+                SetEvent(group.stop_event);
+            }
+            if let Some(thread) = group.thread {
+                let _ = thread.join();
+            }
+        }
+        if !chunks.is_empty() && self.overflow_event.is_none() {
+            self.overflow_event = Some(unsafe { CreateEventW(null_mut(), TRUE, FALSE, null_mut()) });
+        }
+        if let Some(event) = self.overflow_event {
+            for chunk in chunks {
+                self.overflow_groups.push(spawn_overflow_group(chunk, event, self.overflow_ready.clone()));
+            }
+        }
+        self.overflow_signature = signature;
+    }
+
+    /// Pop and fire every timer whose deadline has passed, re-inserting
+    /// repeating timers with an updated deadline.
+    fn fire_timers(&self) {
+        let now = Instant::now();
+        loop {
+            let due = {
+                let mut state = self.handle.0.borrow_mut();
+                match state.timers.peek() {
+                    Some(&Reverse((deadline, id))) if deadline <= now => {
+                        state.timers.pop();
+                        Some(id)
+                    }
+                    _ => None,
+                }
+            };
+            let id = match due {
+                Some(id) => id,
+                None => break,
+            };
+            // Leave the entry in the map while its callback runs, so a
+            // `cancel_timer` from inside the callback still takes effect.
+            let mut callback = match self.handle.0.borrow_mut().timer_entries.get_mut(&id) {
+                Some(entry) => mem::replace(&mut entry.callback, Box::new(|| {})),
+                None => continue,
+            };
+            callback();
+            let mut state = self.handle.0.borrow_mut();
+            match state.timer_entries.get(&id).map(|entry| entry.repeat) {
+                Some(Some(interval)) => {
+                    state.timer_entries.get_mut(&id).unwrap().callback = callback;
+                    state.timers.push(Reverse((Instant::now() + interval, id)));
+                }
+                Some(None) => {
+                    state.timer_entries.remove(&id);
                 }
+                None => {}
             }
         }
     }
 }
 
+/// Spawn a background thread that blocks on `WaitForMultipleObjects` over
+/// `chunk`'s handles (at most `MAX_WAIT_OBJECTS_MAIN`, leaving room for the
+/// thread's own stop event). When one of them is signaled, the thread
+/// records its listener id and calls `SetEvent` on `aggregate_event` so the
+/// main thread's `MsgWaitForMultipleObjectsEx` wakes up and dispatches it.
+fn spawn_overflow_group(
+    chunk: &[(ListenerId, HANDLE)],
+    aggregate_event: HANDLE,
+    ready: Arc<Mutex<VecDeque<ListenerId>>>,
+) -> OverflowGroup {
+    let stop_event = unsafe { CreateEventW(null_mut(), TRUE, FALSE, null_mut()) };
+    let mut ids: Vec<ListenerId> = chunk.iter().map(|&(id, _)| id).collect();
+    // HANDLE is a raw pointer and so isn't `Send`; ferry it across as a
+    // `usize` and cast back inside the thread.
+    let handle_addrs: Vec<usize> = chunk.iter().map(|&(_, h)| h as usize).collect();
+    let stop_addr = stop_event as usize;
+    let aggregate_addr = aggregate_event as usize;
+    let thread = thread::spawn(move || {
+        let mut wait_handles: Vec<HANDLE> = handle_addrs.iter().map(|&h| h as HANDLE).collect();
+        wait_handles.push(stop_addr as HANDLE);
+        loop {
+            let len = wait_handles.len() as u32;
+            let stop_ix = len - 1;
+            let res = unsafe {
+                WaitForMultipleObjects(len, wait_handles.as_ptr(), FALSE, INFINITE)
+            };
+            if res == WAIT_OBJECT_0 + stop_ix {
+                break;
+            }
+            if res >= WAIT_OBJECT_0 && res < WAIT_OBJECT_0 + stop_ix {
+                let ix = (res - WAIT_OBJECT_0) as usize;
+                ready.lock().unwrap().push_back(ids[ix]);
+                unsafe {
+                    SetEvent(aggregate_addr as HANDLE);
+                }
+                // This handle may be level-triggered (e.g. a process
+                // handle stays signaled forever once it exits) and the
+                // listener won't be torn down until the main thread has
+                // processed it and reconciled the overflow groups. Stop
+                // waiting on it locally in the meantime, or we'd busy-spin
+                // rewaking on the same signaled handle.
+                wait_handles.remove(ix);
+                ids.remove(ix);
+            }
+        }
+    });
+    OverflowGroup {
+        stop_event,
+        thread: Some(thread),
+    }
+}
+
+/// Convert a `Duration` into a millisecond timeout suitable for
+/// `MsgWaitForMultipleObjectsEx`, saturating at `INFINITE - 1`. Must never
+/// saturate to `INFINITE` itself, or a deadline far enough out would turn
+/// into "wait forever" and its timer would never fire.
+fn duration_to_millis(d: Duration) -> u32 {
+    let millis = d.as_secs().saturating_mul(1000).saturating_add(u64::from(d.subsec_millis()));
+    let max = u64::from(INFINITE - 1);
+    if millis > max {
+        INFINITE - 1
+    } else {
+        millis as u32
+    }
+}
+
 /// Request to quit the application, exiting the runloop.
 pub fn request_quit() {
     unsafe {
@@ -122,14 +467,23 @@ pub fn request_quit() {
 impl RunLoopHandle {
     /// Add a listener for a Windows handle. Considered unsafe because the
     /// handle must be valid.
-    pub unsafe fn add_handler<F>(&self, h: HANDLE, callback: F)
+    pub unsafe fn add_handler<F>(&self, h: HANDLE, callback: F) -> ListenerToken
         where F: FnMut() + 'static
     {
-        let listener = Listener {
+        let mut state = self.0.borrow_mut();
+        let id = state.next_listener_id;
+        state.next_listener_id += 1;
+        state.listeners.push(Listener {
+            id,
             h,
             callback: Box::new(callback),
-        };
-        self.0.borrow_mut().listeners.push(listener);
+        });
+        ListenerToken(id)
+    }
+
+    /// Remove a listener previously registered with `add_handler`.
+    pub fn remove_handler(&self, token: ListenerToken) {
+        self.0.borrow_mut().listeners.retain(|listener| listener.id != token.0);
     }
 
     /// Add an idle handler, which is called (once) when the message loop
@@ -137,4 +491,273 @@ impl RunLoopHandle {
     pub fn add_idle<F>(&self, callback: F) where F: FnOnce() + 'static {
         self.0.borrow_mut().idle.push(Box::new(callback));
     }
+
+    /// Add a repeating timer that fires `callback` every `interval`,
+    /// starting one `interval` from now.
+    pub fn add_timer<F>(&self, interval: Duration, callback: F) -> TimerToken
+        where F: FnMut() + 'static
+    {
+        let deadline = Instant::now() + interval;
+        self.add_timer_entry(deadline, Some(interval), Box::new(callback))
+    }
+
+    /// Add a one-shot timer that fires `callback` once, at `deadline`.
+    pub fn add_timeout<F>(&self, deadline: Instant, callback: F) -> TimerToken
+        where F: FnOnce() + 'static
+    {
+        let mut callback = Some(callback);
+        self.add_timer_entry(deadline, None, Box::new(move || {
+            if let Some(callback) = callback.take() {
+                callback();
+            }
+        }))
+    }
+
+    fn add_timer_entry(&self, deadline: Instant, repeat: Option<Duration>, callback: Box<FnMut()>) -> TimerToken {
+        let mut state = self.0.borrow_mut();
+        let id = state.next_timer_id;
+        state.next_timer_id += 1;
+        state.timers.push(Reverse((deadline, id)));
+        state.timer_entries.insert(id, TimerEntry { repeat, callback });
+        TimerToken(id)
+    }
+
+    /// Cancel a timer previously registered with `add_timer` or
+    /// `add_timeout`.
+    pub fn cancel_timer(&self, token: TimerToken) {
+        self.0.borrow_mut().timer_entries.remove(&token.0);
+    }
+
+    /// Add a pausable, repeating idle handler, invoked on every pass where
+    /// the message loop is empty, until paused or removed.
+    pub fn add_pausable_idle<F>(&self, callback: F) -> IdleToken
+        where F: FnMut() + 'static
+    {
+        let mut state = self.0.borrow_mut();
+        let id = state.next_idle_id;
+        state.next_idle_id += 1;
+        state.pausable_idle.push(PausableIdle {
+            id,
+            paused: false,
+            callback: Box::new(callback),
+        });
+        IdleToken(id)
+    }
+
+    /// Pause a pausable idle handler so it is no longer invoked, without
+    /// removing it.
+    pub fn pause(&self, token: IdleToken) {
+        self.set_paused(token, true);
+    }
+
+    /// Resume a previously paused idle handler.
+    pub fn resume(&self, token: IdleToken) {
+        self.set_paused(token, false);
+    }
+
+    fn set_paused(&self, token: IdleToken, paused: bool) {
+        let mut state = self.0.borrow_mut();
+        if let Some(idle) = state.pausable_idle.iter_mut().find(|idle| idle.id == token.0) {
+            idle.paused = paused;
+        }
+    }
+
+    /// Remove a pausable idle handler entirely.
+    pub fn remove(&self, token: IdleToken) {
+        self.0.borrow_mut().pausable_idle.retain(|idle| idle.id != token.0);
+    }
+
+    /// Watch `child`'s process handle and invoke `callback` with its exit
+    /// status once it terminates, then automatically unregister the
+    /// listener.
+    pub fn add_process<F>(&self, child: &Child, callback: F)
+        where F: FnOnce(ExitStatus) + 'static
+    {
+        let h = child.as_raw_handle() as HANDLE;
+        let handle = self.clone();
+        let mut callback = Some(callback);
+        let token: Rc<RefCell<Option<ListenerToken>>> = Rc::new(RefCell::new(None));
+        let token_for_callback = token.clone();
+        unsafe {
+            let t = self.add_handler(h, move || {
+                let mut code: DWORD = 0;
+                GetExitCodeProcess(h, &mut code);
+                // Pull everything we still need out to locals *before*
+                // removing the listener: `remove_handler` drops the
+                // `Box<FnMut()>` for this very closure (it's the listener
+                // currently executing), freeing its captured environment,
+                // so nothing captured may be touched afterwards.
+                let cb = callback.take();
+                let status = ExitStatus::from_raw(code);
+                let token = token_for_callback.borrow_mut().take();
+                let handle = handle.clone();
+                if let Some(token) = token {
+                    handle.remove_handler(token);
+                }
+                if let Some(cb) = cb {
+                    cb(status);
+                }
+            });
+            *token.borrow_mut() = Some(t);
+        }
+    }
+}
+
+impl RunLoopProxy {
+    /// Schedule `f` to be run on the run loop's thread. Can be called from
+    /// any thread.
+    pub fn run_on_main<F>(&self, f: F)
+        where F: FnOnce() + Send + 'static
+    {
+        self.queue.lock().unwrap().push_back(Box::new(f));
+        unsafe {
+            SetEvent(self.event);
+        }
+    }
+}
+
+/// A length-prefixed message transport over a pipe `HANDLE`, built on top
+/// of the listener mechanism. Each frame is a little-endian `u32` length
+/// prefix followed by that many payload bytes; partial frames are
+/// retained across wakeups.
+pub struct IpcConnection {
+    handle: RunLoopHandle,
+    pipe: HANDLE,
+    token: ListenerToken,
+}
+
+impl IpcConnection {
+    /// Wrap `pipe` and register it with `handle` so `on_frame` is invoked
+    /// with each complete frame's payload as it arrives. `pipe` must stay
+    /// valid for as long as the connection is registered.
+    pub fn new<F>(handle: &RunLoopHandle, pipe: HANDLE, mut on_frame: F) -> IpcConnection
+        where F: FnMut(&[u8]) + 'static
+    {
+        let buf = Rc::new(RefCell::new(Vec::new()));
+        let token = unsafe {
+            handle.add_handler(pipe, move || {
+                read_available(pipe, &buf);
+                while let Some(frame) = take_frame(&mut buf.borrow_mut()) {
+                    on_frame(&frame);
+                }
+            })
+        };
+        IpcConnection {
+            handle: handle.clone(),
+            pipe,
+            token,
+        }
+    }
+
+    /// Write `payload` to the pipe, preceded by its little-endian `u32`
+    /// length prefix.
+    pub fn send(&self, payload: &[u8]) {
+        let len = (payload.len() as u32).to_le_bytes();
+        unsafe {
+            write_all(self.pipe, &len);
+            write_all(self.pipe, payload);
+        }
+    }
+}
+
+impl Drop for IpcConnection {
+    /// Unregister the listener so it stops reading from `pipe` once the
+    /// connection (and presumably the pipe handle itself) goes away.
+    fn drop(&mut self) {
+        self.handle.remove_handler(self.token);
+    }
+}
+
+/// Read whatever is currently available on `pipe` into `buf`. Uses
+/// `PeekNamedPipe` to size each read to the bytes already buffered by the
+/// OS, so `ReadFile` never blocks waiting for more to arrive — required
+/// since this runs on the run loop's thread in response to a readiness
+/// callback, where blocking would freeze the whole loop.
+unsafe fn read_available(pipe: HANDLE, buf: &Rc<RefCell<Vec<u8>>>) {
+    loop {
+        let mut available: DWORD = 0;
+        let peeked = PeekNamedPipe(pipe, null_mut(), 0, null_mut(), &mut available, null_mut());
+        if peeked == 0 || available == 0 {
+            break;
+        }
+        let mut chunk = vec![0u8; available as usize];
+        let mut read: DWORD = 0;
+        let ok = ReadFile(pipe, chunk.as_mut_ptr() as *mut _, chunk.len() as DWORD, &mut read, null_mut());
+        if ok == 0 || read == 0 {
+            break;
+        }
+        chunk.truncate(read as usize);
+        buf.borrow_mut().extend_from_slice(&chunk);
+    }
+}
+
+/// Pull one complete length-prefixed frame out of the front of `buf`, if
+/// one is fully buffered yet.
+fn take_frame(buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let len = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    if buf.len() < 4 + len {
+        return None;
+    }
+    let frame = buf[4..4 + len].to_vec();
+    buf.drain(..4 + len);
+    Some(frame)
+}
+
+unsafe fn write_all(pipe: HANDLE, mut data: &[u8]) {
+    while !data.is_empty() {
+        let mut written: DWORD = 0;
+        WriteFile(pipe, data.as_ptr() as *const _, data.len() as DWORD, &mut written, null_mut());
+        if written == 0 {
+            break;
+        }
+        data = &data[written as usize..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_frame_waits_for_full_length_prefix() {
+        let mut buf = vec![3, 0, 0];
+        assert_eq!(take_frame(&mut buf), None);
+        assert_eq!(buf, vec![3, 0, 0]);
+    }
+
+    #[test]
+    fn take_frame_waits_for_full_payload() {
+        let mut buf = vec![3, 0, 0, 0, b'h', b'i'];
+        assert_eq!(take_frame(&mut buf), None);
+        assert_eq!(buf.len(), 6);
+    }
+
+    #[test]
+    fn take_frame_splits_one_frame_and_retains_the_rest() {
+        let mut buf = vec![2, 0, 0, 0, b'h', b'i', 9, 9];
+        assert_eq!(take_frame(&mut buf), Some(vec![b'h', b'i']));
+        assert_eq!(buf, vec![9, 9]);
+    }
+
+    #[test]
+    fn take_frame_handles_zero_length_frames() {
+        let mut buf = vec![0, 0, 0, 0, 7, 7];
+        assert_eq!(take_frame(&mut buf), Some(Vec::new()));
+        assert_eq!(buf, vec![7, 7]);
+    }
+
+    #[test]
+    fn duration_to_millis_converts_normally() {
+        assert_eq!(duration_to_millis(Duration::from_millis(1500)), 1500);
+    }
+
+    #[test]
+    fn duration_to_millis_never_saturates_to_infinite() {
+        let huge = Duration::from_secs(u64::from(u32::max_value()));
+        assert_eq!(duration_to_millis(huge), INFINITE - 1);
+        assert_ne!(duration_to_millis(huge), INFINITE);
+    }
 }
\ No newline at end of file